@@ -0,0 +1,75 @@
+use upix_lib::{ApiError, ApiResult};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{ReadableStream, Response};
+use worker::{
+    console_error,
+    js_sys::{Object, Promise, Reflect, Uint8Array},
+    wasm_bindgen_futures::JsFuture,
+    Env,
+};
+
+#[wasm_bindgen]
+extern "C" {
+    /// Images binding (https://developers.cloudflare.com/images/transform-images/bindings/)
+    type ImagesBinding;
+    #[wasm_bindgen(method, catch)]
+    fn input(this: &ImagesBinding, stream: &ReadableStream) -> Result<ImageTransformer, JsValue>;
+
+    type ImageTransformer;
+    #[wasm_bindgen(method)]
+    fn output(this: &ImageTransformer, options: &JsValue) -> Promise;
+
+    type ImageTransformationResult;
+    #[wasm_bindgen(method)]
+    fn response(this: &ImageTransformationResult) -> Response;
+}
+
+/// Converts HEIC/HEIF image data into PNG by the Images binding (`IMAGES`).
+/// HEIF decoders depend on the native libheif, which can't be built for Workers (wasm32-unknown-unknown), so decoding is left to the binding.
+pub async fn convert_to_png(env: &Env, data: &[u8]) -> ApiResult<Vec<u8>> {
+    let binding = Reflect::get(env, &JsValue::from_str("IMAGES"))
+        .ok()
+        .filter(|b| !b.is_undefined());
+    let Some(binding) = binding else {
+        console_error!("failed to get bindings to the Images");
+        return Err(ApiError::no_msg(500));
+    };
+    let images: ImagesBinding = binding.unchecked_into();
+
+    // the binding takes the image as a stream
+    let mut data = data.to_vec();
+    let stream = Response::new_with_opt_u8_array(Some(&mut data))
+        .ok()
+        .and_then(|r| r.body());
+    let Some(stream) = stream else {
+        console_error!("failed to make a stream of the image data");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let opts = Object::new();
+    Reflect::set(&opts, &"format".into(), &"image/png".into()).map_err(|e| {
+        console_error!("failed to set output options: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let output = match images.input(&stream) {
+        Ok(transformer) => JsFuture::from(transformer.output(&opts)).await,
+        Err(e) => Err(e),
+    };
+    // the binding rejects data it can't decode
+    let output: ImageTransformationResult = output
+        .map_err(|e| {
+            console_error!("failed to convert image: {:?}", e);
+            ApiError::new(400, "Failed to decode image")
+        })?
+        .unchecked_into();
+
+    let png = match output.response().array_buffer() {
+        Ok(promise) => JsFuture::from(promise).await,
+        Err(e) => Err(e),
+    };
+    let png = png.map_err(|e| {
+        console_error!("failed to read converted image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(Uint8Array::new(&png).to_vec())
+}
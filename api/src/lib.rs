@@ -1,23 +1,119 @@
-use futures::future;
-use image::{DynamicImage, GenericImageView, ImageError, ImageFormat};
-use serde::Serialize;
+use std::{borrow::Cow, collections::HashMap, ops::RangeInclusive};
+
+use futures::{future, stream, Stream};
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat, Rgba};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
 use worker::{
-    console_error, console_log, event, send::SendWrapper, Bucket, Context, Cors, Env, FormEntry,
-    HttpMetadata, Request, Response, Result as WorkerResult, RouteContext, Router,
+    console_error, console_log, event, send::SendWrapper, Bucket, Context, Cors, Date, Env,
+    FormEntry, Headers, HttpMetadata, Method, Object, Request, RequestInit, Response,
+    Result as WorkerResult, RouteContext, Router, Stub, Url,
+};
+
+use palette_index::{NewIndexEntry, PaletteEntry};
+use rate_limit::RateLimit;
+use upix_lib::{
+    card_scale, contact_sheet_dimensions, encode_image, extract_palette, format_hex_color,
+    key_layout_for_upload, parse_hex_color, record_upload_date, render_card, render_contact_sheet,
+    render_text, sha256_hex, stored_key_layout, text_dimensions, upscale_image, watermark_variant,
+    ApiError, ApiResult, KeyLayout, ZipStream, WATERMARK_META_KEY, WATERMARK_MIN_SCALE,
 };
 
-use upix_lib::{encode_image, sha256_hex, upscale_image, ApiError, ApiResult};
+#[cfg(feature = "heic")]
+mod heic;
+mod palette_index;
+mod rate_limit;
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> WorkerResult<Response> {
     console_error_panic_hook::set_once();
 
-    let router = Router::new();
-    router
+    let features = FeatureFlags::from_env(&env);
+
+    let mut router = Router::new()
         .get("/", handle_get)
-        .post_async("/", handle_post_image)
-        .run(req, env)
-        .await
+        .get_async("/images/:hash", handle_get_image)
+        .get_async("/collections/:id", handle_get_collection);
+    if features.upload {
+        router = router
+            .post_async("/", handle_post_image)
+            .post_async("/collections", handle_post_collection);
+    } else {
+        // `GET /` is always there, so the router would answer `405 Method Not Allowed` otherwise
+        router = router.post("/", handle_disabled);
+    }
+    if features.transforms {
+        router = router
+            .get_async("/images/:hash/card.png", handle_get_card)
+            .get_async("/collections/:id/sheet.png", handle_get_contact_sheet)
+            .get("/render/text", handle_get_text);
+        // storing rendered text is an upload
+        router = if features.upload {
+            router.post_async("/render/text", handle_post_text)
+        } else {
+            router.post("/render/text", handle_disabled)
+        };
+    }
+    if features.bundles {
+        router = router.get_async("/images/:hash/bundle.zip", handle_get_bundle);
+    }
+    if features.listing {
+        router = router.get_async("/palettes", handle_get_palettes);
+    }
+    router.run(req, env).await
+}
+
+/// Groups of endpoints which can be disabled by `ENABLE_*` variables (all enabled by default),
+/// so that e.g. an upload-only instance or a read-only mirror can be deployed from the same code.
+/// Endpoints of disabled groups respond with `404 Not Found`.
+struct FeatureFlags {
+    /// `POST /`, `POST /collections` and `POST /render/text` (`ENABLE_UPLOAD`, the last one also needs `ENABLE_TRANSFORMS`)
+    upload: bool,
+    /// `/images/{hash}/card.png`, `/collections/{id}/sheet.png` and `GET /render/text` (`ENABLE_TRANSFORMS`)
+    transforms: bool,
+    /// `/images/{hash}/bundle.zip` (`ENABLE_BUNDLES`)
+    bundles: bool,
+    /// `/palettes` (`ENABLE_LISTING`)
+    listing: bool,
+}
+
+impl FeatureFlags {
+    fn from_env(env: &Env) -> Self {
+        let enabled = |name: &str| {
+            let Ok(v) = env.var(name).map(|v| v.to_string()) else {
+                return true;
+            };
+            // fail closed, as these are used to lock down endpoints
+            parse_flag(&v).unwrap_or_else(|| {
+                console_error!(
+                    "invalid value of {}: {:?}, disabling the endpoints",
+                    name,
+                    v
+                );
+                false
+            })
+        };
+        Self {
+            upload: enabled("ENABLE_UPLOAD"),
+            transforms: enabled("ENABLE_TRANSFORMS"),
+            bundles: enabled("ENABLE_BUNDLES"),
+            listing: enabled("ENABLE_LISTING"),
+        }
+    }
+}
+
+/// Parses a boolean flag (`true`/`false`, `1`/`0`, `yes`/`no` or `on`/`off`). Returns `None` for any other value.
+fn parse_flag(v: &str) -> Option<bool> {
+    match v.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn handle_disabled(_req: Request, _ctx: RouteContext<()>) -> WorkerResult<Response> {
+    ApiError::no_msg(404).to_response()
 }
 
 fn handle_get(_req: Request, _ctx: RouteContext<()>) -> WorkerResult<Response> {
@@ -37,23 +133,157 @@ fn handle_get(_req: Request, _ctx: RouteContext<()>) -> WorkerResult<Response> {
 // }
 
 async fn handle_post_image(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let checks = async {
+        check_maintenance(&ctx).await?;
+        check_rate_limit(&req, &ctx).await
+    };
+    let rate_limit = match checks.await {
+        Ok(rl) => rl,
+        Err(e) => {
+            return e.to_response().and_then(|r| r.with_cors(&cors()));
+        }
+    };
+
     let res = post_image(req, ctx).await;
-    match res {
-        Ok(images) => Response::from_json(&images),
+    upload_response(res, &rate_limit)
+}
+
+/// Responds with stored variants of the image, with `201 Created` and its location if it's newly created.
+fn upload_response(
+    res: ApiResult<PostImageResult>,
+    rate_limit: &RateLimit,
+) -> WorkerResult<Response> {
+    let mut resp = match res {
+        Ok(res) if res.created => Response::from_json(&res.images).and_then(|r| {
+            let mut r = r.with_status(201);
+            r.headers_mut()
+                .set("Location", &format!("/images/{}", res.hash))?;
+            Ok(r)
+        }),
+        Ok(res) => Response::from_json(&res.images),
         Err(e) => e.to_response(),
+    }?;
+    for (name, value) in rate_limit.headers() {
+        resp.headers_mut().set(name, &value)?;
     }
-    .and_then(|r| r.with_cors(&Cors::default().with_origins(["*"])))
+    resp.with_cors(&cors())
 }
 
-async fn post_image(mut req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<UploadedImage>> {
+/// Key of the maintenance flag in the KV namespace.
+const MAINTENANCE_FLAG_KEY: &str = "maintenance";
+const DEFAULT_MAINTENANCE_MSG: &str = "Service is under maintenance. Please try again later.";
+
+/// Rejects write requests with `503 Service Unavailable` error while the maintenance flag is set in the KV namespace.
+/// Value of the flag is used as the message to clients, if not empty.
+async fn check_maintenance(ctx: &RouteContext<()>) -> ApiResult<()> {
+    let Ok(kv) = ctx.kv("UPIX_KV") else {
+        console_error!("failed to get bindings to the KV namespace");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let flag = match kv.get(MAINTENANCE_FLAG_KEY).text().await {
+        Ok(flag) => flag,
+        Err(e) => {
+            console_error!("failed to read maintenance flag: {:?}", e);
+            None
+        }
+    };
+    let Some(msg) = flag else {
+        return Ok(());
+    };
+
+    let msg = if msg.is_empty() {
+        DEFAULT_MAINTENANCE_MSG.to_string()
+    } else {
+        msg
+    };
+    Err(ApiError::new(503, msg).with_field("error", "maintenance"))
+}
+
+const DEFAULT_UPLOAD_RATE_LIMIT: u32 = 30;
+
+/// Counts an upload request from the client (identified by its IP address) against the rate limit.
+/// The limit per window can be specified by the `UPLOAD_RATE_LIMIT` variable.
+/// Returns `429 Too Many Requests` error with the rate limit state if the client exceeds the limit.
+async fn check_rate_limit(req: &Request, ctx: &RouteContext<()>) -> ApiResult<RateLimit> {
+    let limit = ctx
+        .var("UPLOAD_RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(DEFAULT_UPLOAD_RATE_LIMIT);
+    let client = req
+        .headers()
+        .get("CF-Connecting-IP")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // requests from a client are counted by a dedicated Durable Object, to get a strongly consistent counter
+    let Ok(ns) = ctx.durable_object("UPLOAD_RATE_LIMITER") else {
+        console_error!("failed to get bindings to the rate limiter");
+        return Err(ApiError::no_msg(500));
+    };
+    let Ok(stub) = ns.id_from_name(&client).and_then(|id| id.get_stub()) else {
+        console_error!("failed to get a stub of the rate limiter");
+        return Err(ApiError::no_msg(500));
+    };
+    let mut resp = stub
+        .fetch_with_str(&format!("https://rate-limiter/?limit={}", limit))
+        .await
+        .map_err(|e| {
+            console_error!("failed to call the rate limiter: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    let rate_limit: RateLimit = resp.json().await.map_err(|e| {
+        console_error!("failed to read response from the rate limiter: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+
+    if resp.status_code() == 429 {
+        let retry_after = rate_limit
+            .reset
+            .saturating_sub(Date::now().as_millis() / 1000);
+        let mut err = ApiError::new(429, "Too many requests").with_field(
+            "rate_limit",
+            json!({
+                "limit": rate_limit.limit,
+                "remaining": rate_limit.remaining,
+                "reset": rate_limit.reset,
+                "retry_after": retry_after,
+            }),
+        );
+        for (name, value) in rate_limit.headers() {
+            err = err.with_header(name, value);
+        }
+        return Err(err.with_header("Retry-After", retry_after.to_string()));
+    }
+    Ok(rate_limit)
+}
+
+struct PostImageResult {
+    hash: String,
+    /// `false` if the same image has been uploaded already
+    created: bool,
+    images: Vec<UploadedImage>,
+}
+
+async fn post_image(mut req: Request, ctx: RouteContext<()>) -> ApiResult<PostImageResult> {
     let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
         console_error!("failed to get bindings to the R2 bucket");
         return Err(ApiError::no_msg(500));
     };
     let bucket = SendWrapper::new(bucket);
 
-    let (img_data, img_fmt) = get_image_data_from_request(&mut req).await?;
-    let img = image::load_from_memory_with_format(&img_data, img_fmt).map_err(|e| match e {
+    let (img_data, upload_fmt) = get_image_data_from_request(&mut req).await?;
+    let (decodable, img_fmt) = match upload_fmt {
+        UploadFormat::Image(img_fmt) => (Cow::Borrowed(&img_data[..]), img_fmt),
+        #[cfg(feature = "heic")]
+        UploadFormat::Heif => (
+            Cow::Owned(heic::convert_to_png(&ctx.env, &img_data).await?),
+            ImageFormat::Png,
+        ),
+    };
+    let img = image::load_from_memory_with_format(&decodable, img_fmt).map_err(|e| match e {
         ImageError::Decoding(_) => ApiError::new(400, "Failed to decode image"),
         e => {
             console_error!("failed to load image: {:?}", e);
@@ -62,19 +292,991 @@ async fn post_image(mut req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<Up
     })?;
     validate_img_dimension(&img)?;
 
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+    store_image(&ctx, bucket, img, &img_data, query_flag(&url, "watermark")).await
+}
+
+/// Stores the image and its upscaled variants into the bucket, under the hash of `img_data` (encoded original image).
+async fn store_image(
+    ctx: &RouteContext<()>,
+    bucket: SendWrapper<Bucket>,
+    img: DynamicImage,
+    img_data: &[u8],
+    watermark: bool,
+) -> ApiResult<PostImageResult> {
+    // if the same image has been uploaded already, upload only variants missing from the bucket
+    let hash = sha256_hex(img_data);
+    let (w, h) = img.dimensions();
+    let palette = extract_palette(&img, MAX_PALETTE_COLORS).map(|palette| {
+        let palette: Vec<_> = palette.into_iter().map(format_hex_color).collect();
+        palette.join(",")
+    });
+    let (key_layout, record_date) = key_layout_for_upload(&ctx.env, &hash).await?;
+    let stored = match fetch_original_meta(&bucket, &key_layout, &hash).await? {
+        Some(meta) => {
+            console_log!("image already exists: {}", hash);
+            // the watermark option is fixed at the first upload, so that all variants of the image agree with each other
+            let watermarked = meta.get(WATERMARK_META_KEY).is_some_and(|v| v == "1");
+            if watermarked != watermark {
+                let msg = if watermarked {
+                    "Image has already been uploaded with watermark"
+                } else {
+                    "Image has already been uploaded without watermark"
+                };
+                return Err(ApiError::new(409, msg).with_field("watermark", watermarked));
+            }
+            Some(find_stored_variants(&bucket, &key_layout, &hash, w, h).await?)
+        }
+        None => None,
+    };
+    let missing_scales: Vec<_> = match &stored {
+        Some(stored) => variant_scales(w, h)
+            .filter(|&scale| stored.iter().all(|v| v.scale != scale))
+            .collect(),
+        None => variant_scales(w, h).collect(),
+    };
+    if missing_scales.is_empty() {
+        // recording the date may have failed at the previous upload
+        if record_date {
+            record_upload_date(&ctx.env, &hash, &key_layout).await?;
+        }
+        // also indexes images stored before the palette index was introduced
+        if let Some(palette) = &palette {
+            add_to_palette_index(ctx, palette, &hash).await;
+        }
+        return Ok(PostImageResult {
+            hash,
+            created: false,
+            images: stored.unwrap_or_default(),
+        });
+    }
+
+    // small images have no variant large enough to be watermarked
+    let needs_watermark = watermark && missing_scales.iter().any(|&s| s >= WATERMARK_MIN_SCALE);
+    let watermark_img = if needs_watermark {
+        Some(load_watermark(ctx, &bucket).await?)
+    } else {
+        None
+    };
+
     let uploader = ImageUploader {
         img,
-        hash: sha256_hex(&img_data),
+        hash: hash.clone(),
         dest_fmt: ImageFormat::Png,
         dest_bucket: bucket,
+        key_layout,
+        watermark,
+        watermark_img,
+    };
+    let mut uploaded = uploader
+        .upload_variants(missing_scales)
+        .await
+        .map_err(|_| ApiError::no_msg(500))?;
+    // record the date only after the upload has succeeded, so that a failed upload doesn't leave it behind
+    if record_date {
+        record_upload_date(&ctx.env, &hash, &uploader.key_layout).await?;
+    }
+    if let Some(palette) = &palette {
+        add_to_palette_index(ctx, palette, &hash).await;
+    }
+
+    let Some(mut images) = stored else {
+        return Ok(PostImageResult {
+            hash,
+            created: true,
+            images: uploaded,
+        });
+    };
+    console_log!(
+        "repaired missing variants of {}: {:?}",
+        hash,
+        uploaded.iter().map(|v| v.scale).collect::<Vec<_>>()
+    );
+    for v in &mut uploaded {
+        v.repaired = true;
+    }
+    images.append(&mut uploaded);
+    images.sort_by_key(|v| v.scale);
+    Ok(PostImageResult {
+        hash,
+        created: false,
+        images,
+    })
+}
+
+/// CORS settings for responses to browsers, exposing headers that clients of the API need to read.
+fn cors() -> Cors {
+    Cors::default().with_origins(["*"]).with_exposed_headers([
+        "Location",
+        "Retry-After",
+        "X-RateLimit-Limit",
+        "X-RateLimit-Remaining",
+        "X-RateLimit-Reset",
+    ])
+}
+
+async fn handle_get_image(_req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_image(ctx).await;
+    match res {
+        Ok(meta) => Response::from_json(&meta),
+        Err(e) => e.to_response(),
+    }
+    .and_then(|r| r.with_cors(&cors()))
+}
+
+/// Returns metadata of the image, including variants of it stored in the bucket.
+async fn get_image(ctx: RouteContext<()>) -> ApiResult<ImageMetadata> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let hash = ctx.param("hash").map(String::as_str).unwrap_or_default();
+    let key_layout = resolve_key_layout(&ctx.env, hash).await?;
+    let OriginalImage { img, .. } = fetch_original_image(&bucket, &key_layout, hash).await?;
+    let (w, h) = img.dimensions();
+    let variants = find_stored_variants(&bucket, &key_layout, hash, w, h).await?;
+    Ok(ImageMetadata {
+        hash: hash.to_string(),
+        width: w,
+        height: h,
+        variants,
+    })
+}
+
+async fn handle_get_card(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_card(req, ctx).await;
+    match res {
+        Ok(data) => png_response(data, "public, max-age=86400"),
+        Err(e) => e.to_response(),
+    }
+}
+
+const CARD_WIDTH: u32 = 1200;
+const CARD_HEIGHT: u32 = 630;
+const CARD_PADDING: u32 = 40;
+const DEFAULT_CARD_BG_COLOR: &str = "#ffffff";
+
+/// Renders a social preview card for the image, watermarked if the image was uploaded with the watermark option.
+/// Background color can be specified by the `bg` query parameter, falling back to the `CARD_BG_COLOR` variable.
+async fn get_card(req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<u8>> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let bg = match query_param(&url, "bg") {
+        Some(bg) => bg,
+        None => ctx
+            .var("CARD_BG_COLOR")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| DEFAULT_CARD_BG_COLOR.to_string()),
+    };
+    let Some(bg) = parse_hex_color(&bg) else {
+        return Err(ApiError::new(
+            400,
+            format!("Invalid background color: {}", bg),
+        ));
+    };
+
+    let hash = ctx.param("hash").map(String::as_str).unwrap_or_default();
+    let key_layout = resolve_key_layout(&ctx.env, hash).await?;
+    let OriginalImage { img, watermarked } =
+        fetch_original_image(&bucket, &key_layout, hash).await?;
+    // watermark the art like variants of the same scale factor
+    let scale = card_scale(&img, CARD_WIDTH, CARD_HEIGHT, CARD_PADDING);
+    let watermark = if watermarked && scale >= WATERMARK_MIN_SCALE {
+        Some(load_watermark(&ctx, &bucket).await?)
+    } else {
+        None
+    };
+    let Some(card) = render_card(
+        &img,
+        CARD_WIDTH,
+        CARD_HEIGHT,
+        CARD_PADDING,
+        bg,
+        watermark.as_ref(),
+    ) else {
+        return Err(ApiError::new(400, "Image is too large to fit into a card"));
+    };
+
+    let mut card_data = Vec::new();
+    encode_image(&card, ImageFormat::Png, &mut card_data).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(card_data)
+}
+
+async fn handle_get_bundle(_req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let hash = ctx.param("hash").cloned().unwrap_or_default();
+    let res = get_bundle(hash.clone(), &ctx).await;
+    match res {
+        Ok(bundle) => {
+            let disposition = format!("attachment; filename=\"{}.zip\"", hash);
+            let headers: Headers = [
+                ("Content-Type", "application/zip"),
+                ("Content-Disposition", &disposition),
+                ("Cache-Control", "public, max-age=86400"),
+            ]
+            .iter()
+            .collect();
+            Response::from_stream(bundle.into_stream()).map(|r| r.with_headers(headers))
+        }
+        Err(e) => e.to_response(),
+    }
+}
+
+/// Prepares a ZIP archive of the original image, all stored variants of it and its metadata (`metadata.json`), streamed by `BundleStream`.
+/// Errors found before the response starts (e.g. the image doesn't exist) are reported as error responses as usual,
+/// while a failure in the middle of streaming aborts the response.
+async fn get_bundle(hash: String, ctx: &RouteContext<()>) -> ApiResult<BundleStream> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let key_layout = resolve_key_layout(&ctx.env, &hash).await?;
+    // the original is put into the archive as is, so it's fetched only once
+    let original = fetch_object_data(&bucket, &key_layout.key(&hash, 1, ImageFormat::Png))
+        .await?
+        .ok_or_else(|| {
+            console_log!("image not found: {}", hash);
+            ApiError::no_msg(404)
+        })?;
+    let (w, h) = decode_original_image(&original)?.dimensions();
+
+    Ok(BundleStream {
+        bucket,
+        scales: variant_scales(w, h).collect::<Vec<_>>().into_iter(),
+        original: Some(original),
+        zip: ZipStream::new(),
+        meta: ImageMetadata {
+            hash,
+            width: w,
+            height: h,
+            variants: Vec::new(),
+        },
+        key_layout,
+    })
+}
+
+/// Streams a ZIP bundle of an image, fetching variants one by one so that only one of them is held in memory at a time.
+struct BundleStream {
+    bucket: Bucket,
+    key_layout: KeyLayout,
+    /// scale factors of variants yet to be written
+    scales: std::vec::IntoIter<u32>,
+    /// data of the original image, taken when it's written
+    original: Option<Vec<u8>>,
+    zip: ZipStream,
+    /// metadata written as the last entry, listing variants written so far
+    meta: ImageMetadata,
+}
+
+impl BundleStream {
+    fn into_stream(self) -> impl Stream<Item = WorkerResult<Vec<u8>>> {
+        stream::try_unfold(Some(self), |state| async move {
+            let Some(mut bundle) = state else {
+                return Ok(None);
+            };
+            let next = match bundle.next_variant().await {
+                Ok(Some(chunk)) => Ok(Some((chunk, Some(bundle)))),
+                Ok(None) => bundle.finish().map(|chunk| Some((chunk, None))),
+                Err(e) => Err(e),
+            };
+            // details have been logged already
+            next.map_err(|_| worker::Error::RustError("failed to stream ZIP archive".to_string()))
+        })
+    }
+
+    /// Returns the chunk of the next variant stored in the bucket, or `None` if all variants have been written.
+    async fn next_variant(&mut self) -> ApiResult<Option<Vec<u8>>> {
+        for scale in self.scales.by_ref() {
+            let name = self
+                .key_layout
+                .key(&self.meta.hash, scale, ImageFormat::Png);
+            let data = if scale == 1 {
+                self.original.take()
+            } else {
+                fetch_object_data(&self.bucket, &name).await?
+            };
+            let Some(data) = data else {
+                console_log!("variant is missing, skipped (name: {})", name);
+                continue;
+            };
+
+            let chunk = self.zip.entry(&name, &data);
+            self.meta.variants.push(UploadedImage {
+                scale,
+                width: self.meta.width * scale,
+                height: self.meta.height * scale,
+                repaired: false,
+                name,
+            });
+            return Ok(Some(chunk));
+        }
+        Ok(None)
+    }
+
+    /// Returns the last chunk of the archive, including the metadata.
+    fn finish(mut self) -> ApiResult<Vec<u8>> {
+        let meta_json = serde_json::to_vec_pretty(&self.meta).map_err(|e| {
+            console_error!("failed to serialize metadata: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+        let mut chunk = self.zip.entry("metadata.json", &meta_json);
+        chunk.extend(self.zip.finish());
+        Ok(chunk)
+    }
+}
+
+async fn handle_post_collection(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let checks = async {
+        check_maintenance(&ctx).await?;
+        check_rate_limit(&req, &ctx).await
+    };
+    let rate_limit = match checks.await {
+        Ok(rl) => rl,
+        Err(e) => {
+            return e.to_response().and_then(|r| r.with_cors(&cors()));
+        }
+    };
+
+    let res = post_collection(req, ctx).await;
+    let mut resp = match res {
+        Ok((coll, true)) => Response::from_json(&coll).and_then(|r| {
+            let mut r = r.with_status(201);
+            r.headers_mut()
+                .set("Location", &format!("/collections/{}", coll.id))?;
+            Ok(r)
+        }),
+        Ok((coll, false)) => Response::from_json(&coll),
+        Err(e) => e.to_response(),
+    }?;
+    for (name, value) in rate_limit.headers() {
+        resp.headers_mut().set(name, &value)?;
+    }
+    resp.with_cors(&cors())
+}
+
+/// Max number of images in a collection.
+/// Rendering a contact sheet takes up to 2 subrequests per image (resolving the key layout and fetching the original),
+/// plus ones for the collection and the watermark, so this keeps it within the limit of 50 subrequests per request on the free plan.
+const MAX_COLLECTION_IMAGES: usize = 20;
+
+/// An ordered set of images, which can be rendered into a contact sheet.
+/// Collections are immutable and identified by the hash of their contents, like images.
+#[derive(Debug, Serialize, Deserialize)]
+struct Collection {
+    id: String,
+    images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewCollection {
+    images: Vec<String>,
+}
+
+/// Creates a collection of images specified by the JSON body (`{"images": [<hash>, ...]}`).
+/// Returns the collection and whether it has been newly created.
+async fn post_collection(mut req: Request, ctx: RouteContext<()>) -> ApiResult<(Collection, bool)> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let Ok(NewCollection { images }) = req.json().await else {
+        return Err(ApiError::new(400, "Invalid collection data"));
+    };
+    if images.is_empty() {
+        return Err(ApiError::new(400, "No images specified"));
+    }
+    if images.len() > MAX_COLLECTION_IMAGES {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Too many images ({} > {})",
+                images.len(),
+                MAX_COLLECTION_IMAGES
+            ),
+        ));
+    }
+    if let Some(hash) = images.iter().find(|h| !is_valid_hash(h)) {
+        return Err(ApiError::new(400, format!("Invalid image hash: {}", hash)));
+    }
+    let checks = images.iter().map(|hash| {
+        let bucket = &bucket;
+        let env = &ctx.env;
+        async move {
+            let key_layout = resolve_key_layout(env, hash).await?;
+            Ok::<_, ApiError>((hash, fetch_original_meta(bucket, &key_layout, hash).await?))
+        }
+    });
+    let metas = future::try_join_all(checks).await?;
+    if let Some((hash, _)) = metas.iter().find(|(_, meta)| meta.is_none()) {
+        return Err(ApiError::new(400, format!("Image not found: {}", hash)));
+    }
+
+    let coll = Collection {
+        id: sha256_hex(images.join(",").as_bytes()),
+        images,
+    };
+    let key = collection_key(&coll.id);
+    let exists = bucket
+        .head(&key)
+        .await
+        .map_err(|e| {
+            console_error!("failed to fetch object metadata from the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?
+        .is_some();
+    if exists {
+        return Ok((coll, false));
+    }
+
+    let data = serde_json::to_vec(&coll).map_err(|e| {
+        console_error!("failed to serialize collection: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let meta = HttpMetadata {
+        content_type: Some("application/json".to_string()),
+        ..HttpMetadata::default()
+    };
+    bucket
+        .put(&key, data)
+        .http_metadata(meta)
+        .execute()
+        .await
+        .map_err(|e| {
+            console_error!("failed to upload collection to the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    console_log!("created collection: {}", coll.id);
+    Ok((coll, true))
+}
+
+async fn handle_get_collection(_req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_collection(ctx).await;
+    match res {
+        Ok(coll) => Response::from_json(&coll),
+        Err(e) => e.to_response(),
+    }
+    .and_then(|r| r.with_cors(&cors()))
+}
+
+async fn get_collection(ctx: RouteContext<()>) -> ApiResult<Collection> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
     };
-    let upload_res = uploader.upload_all().await;
-    upload_res.map_err(|_| ApiError::no_msg(500))
+    let id = ctx.param("id").map(String::as_str).unwrap_or_default();
+    fetch_collection(&bucket, id).await
+}
+
+fn collection_key(id: &str) -> String {
+    format!("collections/{}.json", id)
+}
+
+/// Fetches the collection of the given ID from the bucket. Returns `404 Not Found` error if it doesn't exist.
+async fn fetch_collection(bucket: &Bucket, id: &str) -> ApiResult<Collection> {
+    if !is_valid_hash(id) {
+        return Err(ApiError::no_msg(404));
+    }
+    let data = fetch_object_data(bucket, &collection_key(id))
+        .await?
+        .ok_or_else(|| {
+            console_log!("collection not found: {}", id);
+            ApiError::no_msg(404)
+        })?;
+    serde_json::from_slice(&data).map_err(|e| {
+        console_error!("failed to parse collection: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+async fn handle_get_contact_sheet(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_contact_sheet(req, ctx).await;
+    match res {
+        Ok(data) => png_response(data, "public, max-age=86400"),
+        Err(e) => e.to_response(),
+    }
+}
+
+const MAX_SHEET_COLS: u32 = 16;
+const MAX_SHEET_SIDE_LEN: u32 = 4096;
+/// A sheet takes 4 bytes per pixel while rendering, and an upscaled image and the encoded sheet are held along with it,
+/// so the number of pixels is limited to keep them well within the memory limit of Workers (128 MB).
+const MAX_SHEET_PIXELS: u32 = 4 * 1024 * 1024;
+const SHEET_GAP: u32 = 8;
+
+/// Renders images in the collection into a contact sheet. Images uploaded with the watermark option are watermarked.
+/// Number of columns and per-cell scale factor can be specified by the `cols` and `scale` query parameters.
+async fn get_contact_sheet(req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<u8>> {
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let cols = query_u32_param(&url, "cols", 4, 1..=MAX_SHEET_COLS)?;
+    let scale = query_u32_param(&url, "scale", 1, 1..=16)?;
+    let id = ctx.param("id").map(String::as_str).unwrap_or_default();
+    let coll = fetch_collection(&bucket, id).await?;
+
+    let fetches = coll.images.iter().map(|hash| {
+        let bucket = &bucket;
+        let env = &ctx.env;
+        async move {
+            let key_layout = resolve_key_layout(env, hash).await?;
+            fetch_original_image(bucket, &key_layout, hash).await
+        }
+    });
+    let (imgs, watermarked): (Vec<_>, Vec<_>) = future::try_join_all(fetches)
+        .await?
+        .into_iter()
+        .map(|orig| (orig.img, orig.watermarked))
+        .unzip();
+
+    // limit scale factor to avoid generating oversized cells, like variants
+    let long = imgs
+        .iter()
+        .map(|img| u32::max(img.width(), img.height()))
+        .max()
+        .unwrap_or(0);
+    if long * scale > 1024 {
+        return Err(ApiError::new(400, "Scale too big"));
+    }
+    let (w, h) = contact_sheet_dimensions(&imgs, cols, scale, SHEET_GAP);
+    if w > MAX_SHEET_SIDE_LEN || h > MAX_SHEET_SIDE_LEN {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Contact sheet is too large ({} x {}, max side length: {})",
+                w, h, MAX_SHEET_SIDE_LEN
+            ),
+        ));
+    }
+    if w * h > MAX_SHEET_PIXELS {
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Contact sheet has too many pixels ({} > {})",
+                w * h,
+                MAX_SHEET_PIXELS
+            ),
+        ));
+    }
+
+    // watermark images uploaded with the watermark option, like variants of the same scale factor
+    let watermark = if scale >= WATERMARK_MIN_SCALE && watermarked.contains(&true) {
+        Some(load_watermark(&ctx, &bucket).await?)
+    } else {
+        None
+    };
+    let watermarks: Vec<_> = watermarked
+        .iter()
+        .map(|&wm| watermark.as_ref().filter(|_| wm))
+        .collect();
+    let sheet = render_contact_sheet(&imgs, cols, scale, SHEET_GAP, &watermarks);
+    let mut sheet_data = Vec::new();
+    encode_image(&sheet, ImageFormat::Png, &mut sheet_data).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(sheet_data)
+}
+
+fn handle_get_text(req: Request, _ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_text(req);
+    match res {
+        Ok(data) => png_response(data, "public, max-age=86400"),
+        Err(e) => e.to_response(),
+    }
+}
+
+const MAX_TEXT_LEN: usize = 64;
+const DEFAULT_TEXT_FG_COLOR: &str = "#000000";
+const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+struct TextParams {
+    msg: String,
+    fg: Rgba<u8>,
+    bg: Rgba<u8>,
+}
+
+/// Parses the text specified by the `msg` query parameter, and its colors specified by the `fg` and `bg` query parameters
+/// (`bg` also accepts `transparent`, the default).
+fn text_params(url: &Url) -> ApiResult<TextParams> {
+    let Some(msg) = query_param(url, "msg") else {
+        return Err(ApiError::new(400, "Missing 'msg' query parameter"));
+    };
+    if msg.is_empty() {
+        return Err(ApiError::new(400, "Empty message"));
+    }
+    let len = msg.chars().count();
+    if len > MAX_TEXT_LEN {
+        return Err(ApiError::new(
+            400,
+            format!("Message is too long ({} > {} chars)", len, MAX_TEXT_LEN),
+        ));
+    }
+
+    let fg = query_param(url, "fg").unwrap_or_else(|| DEFAULT_TEXT_FG_COLOR.to_string());
+    let Some(fg) = parse_hex_color(&fg) else {
+        return Err(ApiError::new(400, format!("Invalid text color: {}", fg)));
+    };
+    let bg = match query_param(url, "bg") {
+        None => TRANSPARENT,
+        Some(bg) if bg == "transparent" => TRANSPARENT,
+        Some(bg) => parse_hex_color(&bg)
+            .ok_or_else(|| ApiError::new(400, format!("Invalid background color: {}", bg)))?,
+    };
+    Ok(TextParams { msg, fg, bg })
+}
+
+/// Renders the text with the embedded pixel font (see `text_params` for parameters), upscaled by the `scale` factor.
+fn get_text(req: Request) -> ApiResult<Vec<u8>> {
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+    let TextParams { msg, fg, bg } = text_params(&url)?;
+    let scale = query_u32_param(&url, "scale", 1, 1..=16)?;
+
+    // limit scale factor to avoid generating oversized images, like variants
+    let (w, h) = text_dimensions(&msg);
+    if u32::max(w, h) * scale > 1024 {
+        return Err(ApiError::new(400, "Scale too big"));
+    }
+
+    let img = render_text(&msg, fg, bg);
+    let mut data = Vec::new();
+    encode_image(&upscale_image(&img, scale), ImageFormat::Png, &mut data).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(data)
+}
+
+async fn handle_post_text(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let checks = async {
+        check_maintenance(&ctx).await?;
+        check_rate_limit(&req, &ctx).await
+    };
+    let rate_limit = match checks.await {
+        Ok(rl) => rl,
+        Err(e) => {
+            return e.to_response().and_then(|r| r.with_cors(&cors()));
+        }
+    };
+
+    let res = post_text(req, ctx).await;
+    upload_response(res, &rate_limit)
+}
+
+/// Renders the text like `GET /render/text`, and stores the 1x image and its variants into the bucket like uploaded images.
+async fn post_text(req: Request, ctx: RouteContext<()>) -> ApiResult<PostImageResult> {
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+    let TextParams { msg, fg, bg } = text_params(&url)?;
+    let img = render_text(&msg, fg, bg);
+    validate_img_dimension(&img)?;
+
+    let Ok(bucket) = ctx.bucket("IMGS_BUCKET") else {
+        console_error!("failed to get bindings to the R2 bucket");
+        return Err(ApiError::no_msg(500));
+    };
+    let mut img_data = Vec::new();
+    encode_image(&img, ImageFormat::Png, &mut img_data).map_err(|e| {
+        console_error!("failed to encode image: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    let watermark = query_flag(&url, "watermark");
+    store_image(&ctx, SendWrapper::new(bucket), img, &img_data, watermark).await
+}
+
+async fn handle_get_palettes(req: Request, ctx: RouteContext<()>) -> WorkerResult<Response> {
+    let res = get_palettes(req, ctx).await;
+    match res {
+        Ok(palettes) => Response::from_json(&palettes).map(|r| {
+            let headers: Headers = [("Cache-Control", "public, max-age=300")].iter().collect();
+            r.with_headers(headers)
+        }),
+        Err(e) => e.to_response(),
+    }
+    .and_then(|r| r.with_cors(&cors()))
+}
+
+/// Returns palettes in the palette index, most common first.
+/// Number of palettes to return can be specified by the `limit` query parameter.
+async fn get_palettes(req: Request, ctx: RouteContext<()>) -> ApiResult<Vec<PaletteEntry>> {
+    let Ok(url) = req.url() else {
+        console_error!("could not parse the request URL");
+        return Err(ApiError::no_msg(500));
+    };
+    let limit = query_u32_param(&url, "limit", 50, 1..=500)?;
+
+    let mut resp = palette_index_stub(&ctx)?
+        .fetch_with_str(&format!("https://palette-index/?limit={}", limit))
+        .await
+        .map_err(|e| {
+            console_error!("failed to call the palette index: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    resp.json().await.map_err(|e| {
+        console_error!("failed to read response from the palette index: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+/// Adds the image to the palette index, if it's not there yet.
+/// Failures are only logged, as the index is secondary to the upload; uploading the image again indexes it.
+async fn add_to_palette_index(ctx: &RouteContext<()>, palette: &str, hash: &str) {
+    let Ok(stub) = palette_index_stub(ctx) else {
+        return;
+    };
+    let entry = NewIndexEntry {
+        palette: palette.to_string(),
+        hash: hash.to_string(),
+    };
+    let body = match serde_json::to_string(&entry) {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("failed to serialize index entry: {:?}", e);
+            return;
+        }
+    };
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(body.into()));
+    let res = match Request::new_with_init("https://palette-index/", &init) {
+        Ok(req) => stub.fetch_with_request(req).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(resp) if resp.status_code() == 200 => {}
+        Ok(resp) => console_error!("failed to update palette index: {}", resp.status_code()),
+        Err(e) => console_error!("failed to update palette index: {:?}", e),
+    }
+}
+
+/// The palette index is held by a single Durable Object, so that concurrent uploads update it one by one.
+fn palette_index_stub(ctx: &RouteContext<()>) -> ApiResult<Stub> {
+    let Ok(ns) = ctx.durable_object("PALETTE_INDEX") else {
+        console_error!("failed to get bindings to the palette index");
+        return Err(ApiError::no_msg(500));
+    };
+    ns.id_from_name("global")
+        .and_then(|id| id.get_stub())
+        .map_err(|e| {
+            console_error!("failed to get a stub of the palette index: {:?}", e);
+            ApiError::no_msg(500)
+        })
+}
+
+fn png_response(data: Vec<u8>, cache_control: &str) -> WorkerResult<Response> {
+    let headers: Headers = [
+        ("Content-Type", "image/png"),
+        ("Cache-Control", cache_control),
+    ]
+    .iter()
+    .collect();
+    Response::from_bytes(data).map(|r| r.with_headers(headers))
+}
+
+/// Returns the value of the query parameter `name`, if any.
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Parses the query parameter `name` as an integer within the `range`. Returns `default` if the parameter is absent.
+fn query_u32_param(
+    url: &Url,
+    name: &str,
+    default: u32,
+    range: RangeInclusive<u32>,
+) -> ApiResult<u32> {
+    let Some(v) = query_param(url, name) else {
+        return Ok(default);
+    };
+    match v.parse() {
+        Ok(n) if range.contains(&n) => Ok(n),
+        _ => Err(ApiError::new(
+            400,
+            format!(
+                "'{}' must be an integer between {} and {}",
+                name,
+                range.start(),
+                range.end()
+            ),
+        )),
+    }
+}
+
+/// Returns `true` if the query parameter `name` is set to a truthy value (`1` or `true`).
+fn query_flag(url: &Url, name: &str) -> bool {
+    url.query_pairs()
+        .any(|(k, v)| k == name && (v == "1" || v == "true"))
+}
+
+/// Loads the watermark image, whose key is specified by the `WATERMARK_KEY` variable, from the bucket.
+/// Missing watermark is a misconfiguration of the server, so it results in `500 Internal Server Error`.
+async fn load_watermark(ctx: &RouteContext<()>, bucket: &Bucket) -> ApiResult<DynamicImage> {
+    let Ok(key) = ctx.var("WATERMARK_KEY").map(|v| v.to_string()) else {
+        console_error!("WATERMARK_KEY is not configured");
+        return Err(ApiError::no_msg(500));
+    };
+
+    let data = fetch_object_data(bucket, &key).await?.ok_or_else(|| {
+        console_error!("watermark image not found in the bucket (key: {})", key);
+        ApiError::no_msg(500)
+    })?;
+    image::load_from_memory(&data).map_err(|e| {
+        console_error!("failed to decode watermark image: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+fn is_valid_hash(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Resolves the key layout for the image of the given hash. Returns `404 Not Found` error if the hash is invalid.
+async fn resolve_key_layout(env: &Env, hash: &str) -> ApiResult<KeyLayout> {
+    if !is_valid_hash(hash) {
+        return Err(ApiError::no_msg(404));
+    }
+    stored_key_layout(env, hash).await
+}
+
+struct OriginalImage {
+    img: DynamicImage,
+    /// `true` if the image was uploaded with the watermark option
+    watermarked: bool,
+}
+
+/// Fetches the original (1x) image of the given hash from the bucket and decodes it.
+async fn fetch_original_image(
+    bucket: &Bucket,
+    key_layout: &KeyLayout,
+    hash: &str,
+) -> ApiResult<OriginalImage> {
+    let key = key_layout.key(hash, 1, ImageFormat::Png);
+    let obj = fetch_object(bucket, &key).await?.ok_or_else(|| {
+        console_log!("image not found: {}", hash);
+        ApiError::no_msg(404)
+    })?;
+    let meta = obj.custom_metadata().unwrap_or_default();
+    let data = read_object_body(&obj, &key).await?;
+    let img = decode_original_image(&data)?;
+    Ok(OriginalImage {
+        img,
+        watermarked: meta.get(WATERMARK_META_KEY).is_some_and(|v| v == "1"),
+    })
+}
+
+fn decode_original_image(data: &[u8]) -> ApiResult<DynamicImage> {
+    image::load_from_memory_with_format(data, ImageFormat::Png).map_err(|e| {
+        console_error!("failed to decode image: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+/// Fetches the custom metadata of the original image of the given hash. Returns `None` if the original doesn't exist.
+async fn fetch_original_meta(
+    bucket: &Bucket,
+    key_layout: &KeyLayout,
+    hash: &str,
+) -> ApiResult<Option<HashMap<String, String>>> {
+    let key = key_layout.key(hash, 1, ImageFormat::Png);
+    let obj = bucket.head(key).await.map_err(|e| {
+        console_error!("failed to fetch object metadata from the bucket: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(obj.map(|obj| obj.custom_metadata().unwrap_or_default()))
+}
+
+/// Returns variants of the image (whose original has dimensions `w` x `h`) stored in the bucket.
+async fn find_stored_variants(
+    bucket: &Bucket,
+    key_layout: &KeyLayout,
+    hash: &str,
+    w: u32,
+    h: u32,
+) -> ApiResult<Vec<UploadedImage>> {
+    let heads = variant_scales(w, h).map(|scale| async move {
+        let name = key_layout.key(hash, scale, ImageFormat::Png);
+        let obj = bucket.head(name.as_str()).await.map_err(|e| {
+            console_error!("failed to fetch object metadata from the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+        Ok::<_, ApiError>(obj.map(|_| UploadedImage {
+            name,
+            scale,
+            width: w * scale,
+            height: h * scale,
+            repaired: false,
+        }))
+    });
+    let variants = future::try_join_all(heads).await?;
+    Ok(variants.into_iter().flatten().collect())
+}
+
+/// Fetches the content of the object from the bucket. Returns `None` if the object doesn't exist.
+async fn fetch_object_data(bucket: &Bucket, key: &str) -> ApiResult<Option<Vec<u8>>> {
+    let Some(obj) = fetch_object(bucket, key).await? else {
+        return Ok(None);
+    };
+    read_object_body(&obj, key).await.map(Some)
+}
+
+/// Fetches the object, including its body, from the bucket. Returns `None` if the object doesn't exist.
+async fn fetch_object(bucket: &Bucket, key: &str) -> ApiResult<Option<Object>> {
+    bucket.get(key).execute().await.map_err(|e| {
+        console_error!("failed to fetch object from the bucket: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+async fn read_object_body(obj: &Object, key: &str) -> ApiResult<Vec<u8>> {
+    let Some(body) = obj.body() else {
+        console_error!("object doesn't have body (key: {})", key);
+        return Err(ApiError::no_msg(500));
+    };
+    body.bytes().await.map_err(|e| {
+        console_error!("failed to read object body: {:?}", e);
+        ApiError::no_msg(500)
+    })
 }
 
 const MAX_DATA_LEN: usize = 512 * 1024;
 
-async fn get_image_data_from_request(req: &mut Request) -> ApiResult<(Vec<u8>, ImageFormat)> {
+/// Format of uploaded image data.
+#[derive(Debug, Clone, Copy)]
+enum UploadFormat {
+    /// decoded by the `image` crate as is
+    Image(ImageFormat),
+    /// HEIC/HEIF, converted into PNG by the Images binding before decoding
+    #[cfg(feature = "heic")]
+    Heif,
+}
+
+async fn get_image_data_from_request(req: &mut Request) -> ApiResult<(Vec<u8>, UploadFormat)> {
     let Ok(Some(content_type)) = req.headers().get("Content-Type") else {
         return Err(ApiError::new(400, "Missing Content-Type header"));
     };
@@ -89,7 +1291,7 @@ async fn get_image_data_from_request(req: &mut Request) -> ApiResult<(Vec<u8>, I
 async fn get_image_data_from_req_body(
     req: &mut Request,
     ctype: &str,
-) -> ApiResult<(Vec<u8>, ImageFormat)> {
+) -> ApiResult<(Vec<u8>, UploadFormat)> {
     let img_fmt = validate_img_format(ctype)?;
 
     let Ok(img_data) = req.bytes().await else {
@@ -102,7 +1304,7 @@ async fn get_image_data_from_req_body(
     Ok((img_data, img_fmt))
 }
 
-async fn get_image_data_from_form_data(req: &mut Request) -> ApiResult<(Vec<u8>, ImageFormat)> {
+async fn get_image_data_from_form_data(req: &mut Request) -> ApiResult<(Vec<u8>, UploadFormat)> {
     let Ok(form_data) = req.form_data().await else {
         console_error!("could not read form data from the request");
         return Err(ApiError::no_msg(500));
@@ -127,16 +1329,37 @@ async fn get_image_data_from_form_data(req: &mut Request) -> ApiResult<(Vec<u8>,
     Ok((img_data, img_fmt))
 }
 
-fn validate_img_format(content_type: &str) -> ApiResult<ImageFormat> {
-    if !content_type.starts_with("image/") {
+fn validate_img_format(content_type: &str) -> ApiResult<UploadFormat> {
+    // ignore parameters, e.g. "image/heic; charset=binary"
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let Some(subtype) = mime.strip_prefix("image/") else {
         return Err(ApiError::new(400, "Content-Type is not for an image"));
+    };
+    if matches!(subtype, "heic" | "heif" | "heic-sequence" | "heif-sequence") {
+        #[cfg(feature = "heic")]
+        return Ok(UploadFormat::Heif);
+        #[cfg(not(feature = "heic"))]
+        return Err(ApiError::new(
+            400,
+            format!(
+                "Unsupported image format: {} (convert it into PNG or WebP before uploading)",
+                subtype
+            ),
+        ));
     }
-    let Some(img_fmt) = ImageFormat::from_mime_type(content_type) else {
+    let Some(img_fmt) = ImageFormat::from_mime_type(&mime) else {
         return Err(ApiError::new(400, "Content-Type is not for an image"));
     };
 
     match img_fmt {
-        ImageFormat::Png | ImageFormat::WebP | ImageFormat::Bmp | ImageFormat::Gif => Ok(img_fmt),
+        ImageFormat::Png | ImageFormat::WebP | ImageFormat::Bmp | ImageFormat::Gif => {
+            Ok(UploadFormat::Image(img_fmt))
+        }
         _ => Err(ApiError::new(
             400,
             format!("Unsupported image format: {}", img_fmt.extensions_str()[0]),
@@ -179,23 +1402,32 @@ fn validate_img_dimension(img: &DynamicImage) -> ApiResult<()> {
     Ok(())
 }
 
-/// Uploads an image to a bucket. Returns the file name (stem + extension for the image format) of the uploaded image if succeeded.
+/// Uploads a variant of an image to a bucket, under the key following the key layout.
+/// Returns the key of the uploaded image if succeeded.
 #[worker::send]
 async fn upload_image_to_bucket(
-    stem: &str,
+    key_layout: &KeyLayout,
+    hash: &str,
+    scale: u32,
     data: Vec<u8>,
     img_fmt: ImageFormat,
+    custom_meta: HashMap<String, String>,
     bucket: SendWrapper<Bucket>,
 ) -> Result<String, ()> {
-    console_log!("uploading image... (stem: {})", stem);
+    let key = key_layout.key(hash, scale, img_fmt);
+    console_log!("uploading image... (key: {})", key);
 
-    let key = format!("{}.{}", stem, img_fmt.extensions_str()[0]);
     let meta = HttpMetadata {
         content_type: Some(img_fmt.to_mime_type().to_string()),
         ..HttpMetadata::default()
     };
 
-    let put_res = bucket.put(&key, data).http_metadata(meta).execute().await;
+    let put_res = bucket
+        .put(&key, data)
+        .http_metadata(meta)
+        .custom_metadata(custom_meta)
+        .execute()
+        .await;
     match put_res {
         Ok(_) => Ok(key),
         Err(e) => {
@@ -205,36 +1437,61 @@ async fn upload_image_to_bucket(
     }
 }
 
+/// Returns scale factors of variants for an image of the given dimensions, including the original (1x).
+fn variant_scales(w: u32, h: u32) -> impl Iterator<Item = u32> {
+    let long = u32::max(w, h);
+    [1, 2, 4, 8, 16]
+        .into_iter()
+        .take_while(move |&x| long * x <= 1024)
+}
+
 struct ImageUploader {
     img: DynamicImage,
     hash: String,
     dest_fmt: ImageFormat,
     dest_bucket: SendWrapper<Bucket>,
+    key_layout: KeyLayout,
+    /// `true` if the image is uploaded with the watermark option
+    watermark: bool,
+    /// loaded only if any of variants to upload is large enough to be watermarked
+    watermark_img: Option<DynamicImage>,
 }
 
+/// Images with more colors than this are not considered to have a palette.
+const MAX_PALETTE_COLORS: usize = 32;
+
 #[derive(Debug, Serialize)]
 struct UploadedImage {
     name: String,
     scale: u32,
     width: u32,
     height: u32,
+    /// `true` if the variant was missing from the bucket and has been uploaded again
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    repaired: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageMetadata {
+    hash: String,
+    width: u32,
+    height: u32,
+    variants: Vec<UploadedImage>,
 }
 
 impl ImageUploader {
-    async fn upload_all(&self) -> Result<Vec<UploadedImage>, ()> {
-        let (w, h) = self.img.dimensions();
-        let long = u32::max(w, h);
-
-        let tasks = [1, 2, 4, 8, 16]
-            .into_iter()
-            .take_while(|&x| long * x <= 1024)
-            .map(|scale| {
-                if scale == 1 {
-                    Box::pin(self.upload_original_image()) as future::BoxFuture<_>
-                } else {
-                    Box::pin(self.upload_upscaled_image(scale)) as future::BoxFuture<_>
-                }
-            });
+    /// Uploads variants of the given scale factors, including the original if `scales` contains 1.
+    async fn upload_variants(
+        &self,
+        scales: impl IntoIterator<Item = u32>,
+    ) -> Result<Vec<UploadedImage>, ()> {
+        let tasks = scales.into_iter().map(|scale| {
+            if scale == 1 {
+                Box::pin(self.upload_original_image()) as future::BoxFuture<_>
+            } else {
+                Box::pin(self.upload_upscaled_image(scale)) as future::BoxFuture<_>
+            }
+        });
         future::join_all(tasks).await.into_iter().collect()
     }
 
@@ -244,10 +1501,18 @@ impl ImageUploader {
             console_error!("failed to encode image: {:?}", e);
         })?;
 
+        let mut custom_meta = HashMap::new();
+        if self.watermark {
+            custom_meta.insert(WATERMARK_META_KEY.to_string(), "1".to_string());
+        }
+
         let name = upload_image_to_bucket(
+            &self.key_layout,
             &self.hash,
+            1,
             img_data,
             self.dest_fmt,
+            custom_meta,
             self.dest_bucket.clone(),
         )
         .await?;
@@ -258,22 +1523,33 @@ impl ImageUploader {
             scale: 1,
             width: self.img.width(),
             height: self.img.height(),
+            repaired: false,
         })
     }
 
     async fn upload_upscaled_image(&self, scale: u32) -> Result<UploadedImage, ()> {
-        let scaled = upscale_image(&self.img, scale);
+        let mut scaled = upscale_image(&self.img, scale);
+        if let Some(wm) = &self.watermark_img {
+            if scale >= WATERMARK_MIN_SCALE && !watermark_variant(&mut scaled, wm, scale) {
+                console_log!("watermark doesn't fit into {}x image, skipped", scale);
+            }
+        }
 
         let mut img_data = Vec::new();
         encode_image(&scaled, self.dest_fmt, &mut img_data).map_err(|e| {
             console_error!("failed to encode image: {:?}", e);
         })?;
 
-        // stem (file name without extension) is the hash followed by the scale
-        let stem = format!("{}_{}x", self.hash, scale);
-
-        let name = upload_image_to_bucket(&stem, img_data, self.dest_fmt, self.dest_bucket.clone())
-            .await?;
+        let name = upload_image_to_bucket(
+            &self.key_layout,
+            &self.hash,
+            scale,
+            img_data,
+            self.dest_fmt,
+            HashMap::new(),
+            self.dest_bucket.clone(),
+        )
+        .await?;
         console_log!("uploaded {}x upscaled image (name: {})", scale, &name);
 
         Ok(UploadedImage {
@@ -281,6 +1557,52 @@ impl ImageUploader {
             scale,
             width: scaled.width(),
             height: scaled.height(),
+            repaired: false,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{parse_flag, validate_img_format, UploadFormat};
+    use image::ImageFormat;
+
+    #[test]
+    fn test_parse_flag() {
+        assert_eq!(parse_flag("true"), Some(true));
+        assert_eq!(parse_flag("ON"), Some(true));
+        assert_eq!(parse_flag("1"), Some(true));
+        assert_eq!(parse_flag("false"), Some(false));
+        assert_eq!(parse_flag(" no "), Some(false));
+        assert_eq!(parse_flag("0"), Some(false));
+
+        assert_eq!(parse_flag(""), None);
+        assert_eq!(parse_flag("flase"), None);
+        assert_eq!(parse_flag("disabled"), None);
+    }
+
+    #[test]
+    fn test_validate_img_format() {
+        assert!(matches!(
+            validate_img_format("image/png"),
+            Ok(UploadFormat::Image(ImageFormat::Png))
+        ));
+        assert!(matches!(
+            validate_img_format("Image/WebP; foo=bar"),
+            Ok(UploadFormat::Image(ImageFormat::WebP))
+        ));
+        assert!(validate_img_format("text/plain").is_err());
+        assert!(validate_img_format("image/jpeg").is_err());
+
+        for ctype in [
+            "image/heic",
+            "image/heif; charset=binary",
+            "image/heic-sequence",
+        ] {
+            #[cfg(feature = "heic")]
+            assert!(matches!(validate_img_format(ctype), Ok(UploadFormat::Heif)));
+            #[cfg(not(feature = "heic"))]
+            assert!(validate_img_format(ctype).is_err());
+        }
+    }
+}
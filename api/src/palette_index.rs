@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use worker::{js_sys::JSON, wasm_bindgen::JsValue, *};
+
+/// Prefix of keys of palette records in the storage of `PaletteIndex`, followed by the palette.
+const PALETTE_KEY_PREFIX: &str = "palette:";
+/// Prefix of keys of markers of indexed images in the storage of `PaletteIndex`, followed by the hash of the image.
+const IMAGE_KEY_PREFIX: &str = "image:";
+
+/// Max number of images listed in a palette record. Images beyond this are only counted.
+const MAX_PALETTE_IMAGES: usize = 20;
+
+/// Palette and images with the palette, as listed by `GET /palettes`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaletteEntry {
+    pub palette: Vec<String>,
+    pub count: usize,
+    /// hashes of the first `MAX_PALETTE_IMAGES` images indexed with the palette
+    pub images: Vec<String>,
+}
+
+/// Image to add to the index, sent as the body of `POST` requests to `PaletteIndex`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewIndexEntry {
+    /// comma-separated hex colors
+    pub palette: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PaletteRecord {
+    count: usize,
+    images: Vec<String>,
+}
+
+/// Index of images by their palettes, which backs the palette gallery.
+/// A single instance holds the whole index, so that concurrent uploads are indexed one by one without losing any of them.
+#[durable_object]
+pub struct PaletteIndex {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for PaletteIndex {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    /// `POST`: adds the image in the body (`NewIndexEntry`) to the index, if it's not indexed yet.
+    /// `GET`: responds with palettes in the index (`PaletteEntry`), most common first, up to the `limit` query parameter.
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        match req.method() {
+            Method::Post => {
+                let Ok(entry) = req.json::<NewIndexEntry>().await else {
+                    return Response::error("Invalid index entry", 400);
+                };
+                self.add(entry).await?;
+                Response::empty()
+            }
+            Method::Get => {
+                let Some(limit) = req
+                    .url()?
+                    .query_pairs()
+                    .find(|(k, _)| k == "limit")
+                    .and_then(|(_, v)| v.parse::<usize>().ok())
+                else {
+                    return Response::error("Missing 'limit' query parameter", 400);
+                };
+                Response::from_json(&self.list(limit).await?)
+            }
+            _ => Response::error("Method Not Allowed", 405),
+        }
+    }
+}
+
+impl PaletteIndex {
+    async fn add(&mut self, entry: NewIndexEntry) -> Result<()> {
+        let image_key = format!("{}{}", IMAGE_KEY_PREFIX, entry.hash);
+        let palette_key = format!("{}{}", PALETTE_KEY_PREFIX, entry.palette);
+        let mut storage = self.state.storage();
+        // unlike `get`, this tells missing keys from failures, so that a failure never resets the record
+        let values = storage
+            .get_multiple(vec![image_key.as_str(), palette_key.as_str()])
+            .await?;
+        if values.has(&JsValue::from_str(&image_key)) {
+            return Ok(());
+        }
+
+        let mut record = match values.get(&JsValue::from_str(&palette_key)) {
+            v if v.is_undefined() => PaletteRecord::default(),
+            v => parse_record(&v).ok_or_else(|| {
+                Error::RustError(format!("broken palette record: {}", entry.palette))
+            })?,
+        };
+        record.count += 1;
+        if record.images.len() < MAX_PALETTE_IMAGES {
+            record.images.push(entry.hash);
+        }
+        // mark the image last, so that it's indexed again if updating the record fails
+        storage.put(&palette_key, record).await?;
+        storage.put(&image_key, entry.palette).await
+    }
+
+    async fn list(&self, limit: usize) -> Result<Vec<PaletteEntry>> {
+        let records = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix(PALETTE_KEY_PREFIX))
+            .await?;
+
+        let mut entries = Vec::new();
+        records.for_each(&mut |record, key| {
+            let Some(palette) = key
+                .as_string()
+                .and_then(|k| k.strip_prefix(PALETTE_KEY_PREFIX).map(String::from))
+            else {
+                return;
+            };
+            let Some(record) = parse_record(&record) else {
+                console_error!("broken palette record: {}", palette);
+                return;
+            };
+            entries.push(PaletteEntry {
+                palette: palette.split(',').map(String::from).collect(),
+                count: record.count,
+                images: record.images,
+            });
+        });
+
+        entries.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.palette.cmp(&b.palette))
+        });
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
+
+/// Values listed from the storage are JS objects, so convert them via JSON.
+fn parse_record(value: &JsValue) -> Option<PaletteRecord> {
+    let json = String::from(JSON::stringify(value).ok()?);
+    serde_json::from_str(&json).ok()
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+
+/// Key of the counter in the storage of `UploadRateLimiter`: `(window, count)`
+const COUNTER_KEY: &str = "counter";
+
+/// State of the fixed-window rate limit for a client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    /// UNIX time (in seconds) when the current window ends
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            ("X-RateLimit-Reset", self.reset.to_string()),
+        ]
+    }
+}
+
+/// Counts uploads from a client in fixed windows.
+/// An instance is created for each client, so requests from the client are counted one by one with a strongly consistent counter.
+#[durable_object]
+pub struct UploadRateLimiter {
+    state: State,
+    /// `(window, count)`, loaded from the storage on the first request to the instance
+    counter: Option<(u64, u32)>,
+}
+
+#[durable_object]
+impl DurableObject for UploadRateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            state,
+            counter: None,
+        }
+    }
+
+    /// Counts a request against the limit specified by the `limit` query parameter, and responds with the state of the rate limit.
+    /// Responds with `429 Too Many Requests` without counting the request if the client has reached the limit.
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let Some(limit) = req
+            .url()?
+            .query_pairs()
+            .find(|(k, _)| k == "limit")
+            .and_then(|(_, v)| v.parse::<u32>().ok())
+        else {
+            return Response::error("Missing 'limit' query parameter", 400);
+        };
+
+        let now = Date::now().as_millis() / 1000;
+        let window = now / RATE_LIMIT_WINDOW_SECS;
+        let reset = (window + 1) * RATE_LIMIT_WINDOW_SECS;
+
+        let counter = match self.counter {
+            Some(counter) => Some(counter),
+            // the instance may have been evicted in the middle of a window
+            None => self.state.storage().get(COUNTER_KEY).await.ok(),
+        };
+        let count = match counter {
+            Some((w, count)) if w == window => count,
+            _ => 0,
+        };
+        if count >= limit {
+            let rate_limit = RateLimit {
+                limit,
+                remaining: 0,
+                reset,
+            };
+            return Response::from_json(&rate_limit).map(|r| r.with_status(429));
+        }
+
+        let count = count + 1;
+        self.counter = Some((window, count));
+        self.state
+            .storage()
+            .put(COUNTER_KEY, (window, count))
+            .await?;
+
+        Response::from_json(&RateLimit {
+            limit,
+            remaining: limit - count,
+            reset,
+        })
+    }
+}
@@ -1,6 +1,9 @@
 use regex::Regex;
 use send::SendWrapper;
-use upix_lib::{encode_image, sha256_hex, upscale_image, ApiError, ApiResult};
+use upix_lib::{
+    encode_image, sha256_hex, stored_key_layout, upscale_image, watermark_variant, ApiError,
+    ApiResult, WATERMARK_META_KEY, WATERMARK_MIN_SCALE,
+};
 use worker::*;
 
 #[event(fetch)]
@@ -46,7 +49,7 @@ async fn handle(req: Request, env: Env, ctx: Context) -> ApiResult<Response> {
     }
 
     // generate a response with upscaled image
-    let img_data = generate_upscaled_image(&req.path(), bucket).await?;
+    let img_data = generate_upscaled_image(&req.path(), &env, bucket).await?;
     let hash = sha256_hex(&img_data);
 
     let resp_headers: Headers = [
@@ -74,6 +77,7 @@ async fn handle(req: Request, env: Env, ctx: Context) -> ApiResult<Response> {
 
 async fn generate_upscaled_image(
     req_path: &str,
+    env: &Env,
     bucket: SendWrapper<Bucket>,
 ) -> ApiResult<Vec<u8>> {
     let Some(parts) = match_req_path(req_path) else {
@@ -86,8 +90,9 @@ async fn generate_upscaled_image(
     }
 
     // get source image data from the bucket
-    let src_img_data = bucket
-        .get(format!("{}.png", parts.hash))
+    let key_layout = stored_key_layout(env, &parts.hash).await?;
+    let src_obj = bucket
+        .get(key_layout.key(&parts.hash, 1, image::ImageFormat::Png))
         .execute()
         .await
         .map_err(|e| {
@@ -97,7 +102,11 @@ async fn generate_upscaled_image(
         .ok_or_else(|| {
             console_log!("Image not found: {}", parts.hash);
             ApiError::no_msg(404)
-        })?
+        })?;
+    let watermarked = src_obj
+        .custom_metadata()
+        .is_ok_and(|meta| meta.get(WATERMARK_META_KEY).is_some_and(|v| v == "1"));
+    let src_img_data = src_obj
         .body()
         .ok_or_else(|| {
             console_error!("Object doesn't have body");
@@ -123,12 +132,20 @@ async fn generate_upscaled_image(
         return Err(ApiError::new(400, "Scale too big"));
     }
 
-    let upscaled_img = if parts.scale == 1 {
+    let mut upscaled_img = if parts.scale == 1 {
         src_img
     } else {
         upscale_image(&src_img, parts.scale)
     };
 
+    // watermark larger images in the same way as variants stored by upix-api
+    if watermarked && parts.scale >= WATERMARK_MIN_SCALE {
+        let watermark = load_watermark(env, &bucket).await?;
+        if !watermark_variant(&mut upscaled_img, &watermark, parts.scale) {
+            console_log!("Watermark doesn't fit into {}x image, skipped", parts.scale);
+        }
+    }
+
     let mut upscaled_img_data = Vec::new();
     encode_image(
         &upscaled_img,
@@ -142,6 +159,42 @@ async fn generate_upscaled_image(
     Ok(upscaled_img_data)
 }
 
+/// Loads the watermark image, whose key is specified by the `WATERMARK_KEY` variable, from the bucket.
+async fn load_watermark(env: &Env, bucket: &Bucket) -> ApiResult<image::DynamicImage> {
+    // never serve unwatermarked images of watermarked ones
+    let Ok(key) = env.var("WATERMARK_KEY").map(|v| v.to_string()) else {
+        console_error!("WATERMARK_KEY is not configured");
+        return Err(ApiError::no_msg(500));
+    };
+    let data = bucket
+        .get(&key)
+        .execute()
+        .await
+        .map_err(|e| {
+            console_error!("Failed to fetch watermark from the bucket: {:?}", e);
+            ApiError::no_msg(500)
+        })?
+        .ok_or_else(|| {
+            console_error!("Watermark image not found in the bucket (key: {})", key);
+            ApiError::no_msg(500)
+        })?
+        .body()
+        .ok_or_else(|| {
+            console_error!("Object doesn't have body");
+            ApiError::no_msg(500)
+        })?
+        .bytes()
+        .await
+        .map_err(|e| {
+            console_error!("Failed to read object body: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    image::load_from_memory(&data).map_err(|e| {
+        console_error!("Failed to decode watermark image: {:?}", e);
+        ApiError::no_msg(500)
+    })
+}
+
 struct ReqPathParts {
     hash: String,
     scale: u32,
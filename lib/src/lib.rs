@@ -1,9 +1,21 @@
-use std::io::Cursor;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+};
 
-use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageError, ImageFormat};
-use serde_json::json;
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageError, ImageFormat, Rgba, RgbaImage,
+};
+use serde_json::{json, Map, Value};
 use sha2::{Digest, Sha256};
-use worker::{Response, Result as WorkerResult};
+use worker::{console_error, Bucket, Date, Env, Response, Result as WorkerResult};
+
+mod font;
+mod zip_stream;
+pub use font::{render_text, text_dimensions};
+pub use zip_stream::ZipStream;
 
 /// Encode the `DynamicImage` into a `dest` buffer with the given format.
 pub fn encode_image(
@@ -21,10 +33,188 @@ pub fn upscale_image(img: &DynamicImage, scale: u32) -> DynamicImage {
     img.resize(w * scale, h * scale, FilterType::Nearest)
 }
 
+/// Composite the watermark onto the bottom-right corner of the image, upscaling the watermark by `wm_scale`.
+/// The watermark is kept `margin` pixels away from the edges. Returns `false` and leaves the image untouched if the watermark doesn't fit.
+pub fn overlay_watermark(
+    img: &mut DynamicImage,
+    watermark: &DynamicImage,
+    wm_scale: u32,
+    margin: u32,
+) -> bool {
+    let (w, h) = img.dimensions();
+    let (wm_w, wm_h) = (watermark.width() * wm_scale, watermark.height() * wm_scale);
+    if wm_w + margin * 2 > w || wm_h + margin * 2 > h {
+        return false;
+    }
+
+    let wm = upscale_image(watermark, wm_scale);
+    let x = w - wm_w - margin;
+    let y = h - wm_h - margin;
+    imageops::overlay(img, &wm, i64::from(x), i64::from(y));
+    true
+}
+
+/// Watermark is composited only into variants upscaled by this factor or more.
+pub const WATERMARK_MIN_SCALE: u32 = 4;
+/// Key of the custom metadata of original images that is set to `"1"` if the image was uploaded with the watermark option.
+pub const WATERMARK_META_KEY: &str = "watermark";
+
+/// Composite the watermark into the variant of an image upscaled by `scale` (`WATERMARK_MIN_SCALE` or more).
+/// The watermark is scaled up along with the variant to keep its pixels as crisp as the art. Returns `false` if it doesn't fit.
+pub fn watermark_variant(variant: &mut DynamicImage, watermark: &DynamicImage, scale: u32) -> bool {
+    overlay_watermark(variant, watermark, scale / WATERMARK_MIN_SCALE, scale)
+}
+
+/// Returns the largest integer factor to upscale the image by, keeping it at least `padding` pixels away from the edges of
+/// a `width` x `height` card. Returns 0 if the image doesn't fit even at 1x.
+pub fn card_scale(img: &DynamicImage, width: u32, height: u32, padding: u32) -> u32 {
+    let (w, h) = img.dimensions();
+    let (area_w, area_h) = (
+        width.saturating_sub(padding * 2),
+        height.saturating_sub(padding * 2),
+    );
+    u32::min(area_w / w, area_h / h)
+}
+
+/// Render the image centered on a `width` x `height` canvas filled with `bg`, upscaled by `card_scale`.
+/// If `watermark` is given, it's composited into the upscaled image like variants, when the scale factor is `WATERMARK_MIN_SCALE` or more.
+/// Returns `None` if the image doesn't fit even at 1x, as shrinking it would distort its pixels.
+pub fn render_card(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    padding: u32,
+    bg: Rgba<u8>,
+    watermark: Option<&DynamicImage>,
+) -> Option<DynamicImage> {
+    let scale = card_scale(img, width, height, padding);
+    if scale == 0 {
+        return None;
+    }
+
+    let mut art = upscale_image(img, scale);
+    if let Some(wm) = watermark.filter(|_| scale >= WATERMARK_MIN_SCALE) {
+        watermark_variant(&mut art, wm, scale);
+    }
+    let mut card = RgbaImage::from_pixel(width, height, bg);
+    let x = (width - art.width()) / 2;
+    let y = (height - art.height()) / 2;
+    imageops::overlay(&mut card, &art.to_rgba8(), i64::from(x), i64::from(y));
+    Some(DynamicImage::ImageRgba8(card))
+}
+
+/// Calculate the dimensions of a contact sheet rendered by `render_contact_sheet` with the same parameters.
+pub fn contact_sheet_dimensions(
+    imgs: &[DynamicImage],
+    cols: u32,
+    scale: u32,
+    gap: u32,
+) -> (u32, u32) {
+    let (cols, rows) = sheet_grid_size(imgs.len() as u32, cols);
+    let (cell_w, cell_h) = sheet_cell_size(imgs, scale);
+    (
+        cols * cell_w + (cols + 1) * gap,
+        rows * cell_h + (rows + 1) * gap,
+    )
+}
+
+/// Render images into a single contact sheet, arranged in a grid with `cols` columns.
+/// Each image is upscaled by `scale` and centered in a cell that fits the largest image, and cells are separated by `gap` pixels.
+/// `watermarks[i]`, if any, is composited into the i-th image like variants, when `scale` is `WATERMARK_MIN_SCALE` or more.
+pub fn render_contact_sheet(
+    imgs: &[DynamicImage],
+    cols: u32,
+    scale: u32,
+    gap: u32,
+    watermarks: &[Option<&DynamicImage>],
+) -> DynamicImage {
+    let (sheet_w, sheet_h) = contact_sheet_dimensions(imgs, cols, scale, gap);
+    let (cols, _) = sheet_grid_size(imgs.len() as u32, cols);
+    let (cell_w, cell_h) = sheet_cell_size(imgs, scale);
+
+    let mut sheet = RgbaImage::new(sheet_w, sheet_h);
+    for (i, img) in (0u32..).zip(imgs) {
+        let mut scaled = upscale_image(img, scale);
+        if let Some(Some(wm)) = watermarks
+            .get(i as usize)
+            .filter(|_| scale >= WATERMARK_MIN_SCALE)
+        {
+            watermark_variant(&mut scaled, wm, scale);
+        }
+        let (col, row) = (i % cols, i / cols);
+        let x = gap + col * (cell_w + gap) + (cell_w - scaled.width()) / 2;
+        let y = gap + row * (cell_h + gap) + (cell_h - scaled.height()) / 2;
+        imageops::overlay(&mut sheet, &scaled.to_rgba8(), i64::from(x), i64::from(y));
+    }
+    DynamicImage::ImageRgba8(sheet)
+}
+
+/// Returns the number of columns and rows of the contact sheet grid.
+fn sheet_grid_size(n: u32, cols: u32) -> (u32, u32) {
+    let cols = u32::min(cols, n).max(1);
+    (cols, n.div_ceil(cols))
+}
+
+fn sheet_cell_size(imgs: &[DynamicImage], scale: u32) -> (u32, u32) {
+    let w = imgs.iter().map(|img| img.width()).max().unwrap_or(0);
+    let h = imgs.iter().map(|img| img.height()).max().unwrap_or(0);
+    (w * scale, h * scale)
+}
+
+/// Extract the palette (set of distinct colors) of the image, sorted in ascending order of RGBA values.
+/// Fully transparent pixels are ignored. Returns `None` if the image has more than `max_colors` colors.
+pub fn extract_palette(img: &DynamicImage, max_colors: usize) -> Option<Vec<Rgba<u8>>> {
+    let mut colors = BTreeSet::new();
+    for px in img.to_rgba8().pixels().filter(|px| px[3] != 0) {
+        colors.insert(px.0);
+        if colors.len() > max_colors {
+            return None;
+        }
+    }
+    Some(colors.into_iter().map(Rgba).collect())
+}
+
+/// Format the color in the hex notation (`#rrggbb`, or `#rrggbbaa` if the color is not opaque).
+pub fn format_hex_color(color: Rgba<u8>) -> String {
+    let [r, g, b, a] = color.0;
+    if a == 255 {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// Parse a color in the hex notation (`#rgb`, `#rrggbb` or `#rrggbbaa`, with or without leading `#`).
+pub fn parse_hex_color(s: &str) -> Option<Rgba<u8>> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize, len: usize| {
+        let v = u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).ok()?;
+        // expand short notation: "f" -> "ff"
+        Some(if len == 1 { v * 17 } else { v })
+    };
+
+    match hex.len() {
+        3 => Some(Rgba([channel(0, 1)?, channel(1, 1)?, channel(2, 1)?, 255])),
+        6 => Some(Rgba([channel(0, 2)?, channel(1, 2)?, channel(2, 2)?, 255])),
+        8 => Some(Rgba([
+            channel(0, 2)?,
+            channel(1, 2)?,
+            channel(2, 2)?,
+            channel(3, 2)?,
+        ])),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct ApiError {
     status: u16,
     message: Option<String>,
+    fields: Map<String, Value>,
+    headers: Vec<(&'static str, String)>,
 }
 
 impl ApiError {
@@ -32,29 +222,541 @@ impl ApiError {
         Self {
             status,
             message: Some(msg.into()),
+            fields: Map::new(),
+            headers: Vec::new(),
         }
     }
     pub fn no_msg(status: u16) -> Self {
         Self {
             status,
             message: None,
+            fields: Map::new(),
+            headers: Vec::new(),
         }
     }
 
+    /// Add a field to the JSON body of the error response, alongside the message.
+    pub fn with_field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+
+    /// Add a header to the error response.
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
     pub fn to_response(&self) -> WorkerResult<Response> {
         let r = match &self.message {
-            None => Response::empty(),
-            Some(msg) => Response::from_json(&json!({ "message": msg })),
+            None if self.fields.is_empty() => Response::empty(),
+            msg => {
+                let mut body = self.fields.clone();
+                if let Some(msg) = msg {
+                    body.insert("message".to_string(), json!(msg));
+                }
+                Response::from_json(&body)
+            }
         };
-        r.map(|r| r.with_status(self.status))
+        let mut r = r?.with_status(self.status);
+        for (name, value) in &self.headers {
+            r.headers_mut().set(name, value)?;
+        }
+        Ok(r)
     }
 }
 
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
+/// Date (in UTC) when an image was uploaded for the first time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadDate {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl UploadDate {
+    pub fn today() -> Self {
+        Self::from_unix_secs(Date::now().as_millis() / 1000)
+    }
+
+    pub fn from_unix_secs(secs: u64) -> Self {
+        // convert days since the UNIX epoch to the civil date (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+        let z = secs / 86400 + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        Self {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+        }
+    }
+
+    /// Parse a date in the `YYYY-MM-DD` format.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(3, '-').map(|p| p.parse().ok());
+        let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+        Some(Self { year, month, day })
+    }
+}
+
+impl Display for UploadDate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeySegment {
+    Literal(String),
+    Hash,
+    Scale,
+    ScaleSuffix,
+    Ext,
+    Year,
+    Month,
+    Day,
+}
+
+/// Template of keys of objects in the bucket. Available placeholders are:
+///
+/// - `{hash}`: SHA-256 hash of the image
+/// - `{scale}`: scale factor of the variant
+/// - `{sx}`: empty for the original, `_{scale}x` for upscaled variants
+/// - `{ext}`: file extension for the image format
+/// - `{yyyy}`, `{mm}`, `{dd}`: date when the image was uploaded for the first time
+#[derive(Debug, Clone)]
+pub struct ObjectKeyTemplate(Vec<KeySegment>);
+
+impl ObjectKeyTemplate {
+    /// Names the original `{hash}.png` and upscaled variants `{hash}_{scale}x.png`.
+    pub const DEFAULT: &'static str = "{hash}{sx}.{ext}";
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(KeySegment::Literal(rest[..start].to_string()));
+            }
+            let Some(len) = rest[start..].find('}') else {
+                return Err("unclosed placeholder".to_string());
+            };
+            let seg = match &rest[start + 1..start + len] {
+                "hash" => KeySegment::Hash,
+                "scale" => KeySegment::Scale,
+                "sx" => KeySegment::ScaleSuffix,
+                "ext" => KeySegment::Ext,
+                "yyyy" => KeySegment::Year,
+                "mm" => KeySegment::Month,
+                "dd" => KeySegment::Day,
+                name => return Err(format!("unknown placeholder: {{{}}}", name)),
+            };
+            segments.push(seg);
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(KeySegment::Literal(rest.to_string()));
+        }
+
+        // keys must be unique for each variant of each image
+        if !segments.contains(&KeySegment::Hash) {
+            return Err("template must contain {hash}".to_string());
+        }
+        if !segments.contains(&KeySegment::Scale) && !segments.contains(&KeySegment::ScaleSuffix) {
+            return Err("template must contain either {scale} or {sx}".to_string());
+        }
+        Ok(Self(segments))
+    }
+
+    /// Returns `true` if the template contains any of date placeholders.
+    pub fn uses_date(&self) -> bool {
+        self.0
+            .iter()
+            .any(|seg| matches!(seg, KeySegment::Year | KeySegment::Month | KeySegment::Day))
+    }
+
+    pub fn render(&self, hash: &str, scale: u32, ext: &str, date: &UploadDate) -> String {
+        let mut key = String::new();
+        for seg in &self.0 {
+            match seg {
+                KeySegment::Literal(s) => key.push_str(s),
+                KeySegment::Hash => key.push_str(hash),
+                KeySegment::Scale => key.push_str(&scale.to_string()),
+                KeySegment::ScaleSuffix if scale == 1 => {}
+                KeySegment::ScaleSuffix => key.push_str(&format!("_{}x", scale)),
+                KeySegment::Ext => key.push_str(ext),
+                KeySegment::Year => key.push_str(&format!("{:04}", date.year)),
+                KeySegment::Month => key.push_str(&format!("{:02}", date.month)),
+                KeySegment::Day => key.push_str(&format!("{:02}", date.day)),
+            }
+        }
+        key
+    }
+}
+
+impl Default for ObjectKeyTemplate {
+    fn default() -> Self {
+        Self::parse(Self::DEFAULT).unwrap()
+    }
+}
+
+/// Layout of keys of objects for an image, determined by the key template and the upload date of the image.
+#[derive(Debug, Clone)]
+pub struct KeyLayout {
+    template: ObjectKeyTemplate,
+    date: UploadDate,
+}
+
+impl KeyLayout {
+    /// Layout for the template without date placeholders.
+    fn undated(template: ObjectKeyTemplate) -> Self {
+        Self {
+            template,
+            date: UploadDate::from_unix_secs(0),
+        }
+    }
+
+    /// Returns the key of the variant of the image with the given scale factor and format.
+    pub fn key(&self, hash: &str, scale: u32, img_fmt: ImageFormat) -> String {
+        self.template
+            .render(hash, scale, img_fmt.extensions_str()[0], &self.date)
+    }
+}
+
+/// Prefix of keys of objects that record upload dates of images in their custom metadata.
+/// Dates are kept in the bucket, rather than in KV, so that they are readable from anywhere right after uploading.
+const UPLOAD_DATE_KEY_PREFIX: &str = "upload-dates/";
+const UPLOAD_DATE_META_KEY: &str = "date";
+
+/// Reads the key template from the `OBJECT_KEY_TEMPLATE` variable, falling back to the default one.
+pub fn object_key_template(env: &Env) -> ApiResult<ObjectKeyTemplate> {
+    let Ok(template) = env.var("OBJECT_KEY_TEMPLATE") else {
+        return Ok(ObjectKeyTemplate::default());
+    };
+    ObjectKeyTemplate::parse(&template.to_string()).map_err(|e| {
+        console_error!("invalid OBJECT_KEY_TEMPLATE: {}", e);
+        ApiError::no_msg(500)
+    })
+}
+
+/// Returns the key layout for the image stored in the bucket.
+/// If the template requires the upload date but it's not recorded for the image, the image is assumed to be stored in the default layout,
+/// as images stored before switching to such a template are.
+pub async fn stored_key_layout(env: &Env, hash: &str) -> ApiResult<KeyLayout> {
+    let template = object_key_template(env)?;
+    if !template.uses_date() {
+        // the date is never rendered into keys
+        return Ok(KeyLayout::undated(template));
+    }
+
+    match recorded_upload_date(&imgs_bucket(env)?, hash).await? {
+        Some(date) => Ok(KeyLayout { template, date }),
+        None => Ok(KeyLayout::undated(ObjectKeyTemplate::default())),
+    }
+}
+
+/// Returns the key layout for uploading the image, and whether the upload date has to be recorded by `record_upload_date`
+/// after the original is stored. New images get today as the upload date, while ones stored in the default layout keep it.
+pub async fn key_layout_for_upload(env: &Env, hash: &str) -> ApiResult<(KeyLayout, bool)> {
+    let template = object_key_template(env)?;
+    if !template.uses_date() {
+        return Ok((KeyLayout::undated(template), false));
+    }
+
+    let bucket = imgs_bucket(env)?;
+    if let Some(date) = recorded_upload_date(&bucket, hash).await? {
+        return Ok((KeyLayout { template, date }, false));
+    }
+    let default_layout = KeyLayout::undated(ObjectKeyTemplate::default());
+    let default_key = default_layout.key(hash, 1, ImageFormat::Png);
+    let stored = bucket.head(default_key).await.map_err(|e| {
+        console_error!("failed to fetch object metadata from the bucket: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    if stored.is_some() {
+        return Ok((default_layout, false));
+    }
+
+    let layout = KeyLayout {
+        template,
+        date: UploadDate::today(),
+    };
+    Ok((layout, true))
+}
+
+/// Records the upload date of the key layout for the image.
+pub async fn record_upload_date(env: &Env, hash: &str, layout: &KeyLayout) -> ApiResult<()> {
+    let meta = HashMap::from([(UPLOAD_DATE_META_KEY.to_string(), layout.date.to_string())]);
+    imgs_bucket(env)?
+        .put(
+            format!("{}{}", UPLOAD_DATE_KEY_PREFIX, hash),
+            Vec::<u8>::new(),
+        )
+        .custom_metadata(meta)
+        .execute()
+        .await
+        .map_err(|e| {
+            console_error!("failed to record upload date: {:?}", e);
+            ApiError::no_msg(500)
+        })?;
+    Ok(())
+}
+
+async fn recorded_upload_date(bucket: &Bucket, hash: &str) -> ApiResult<Option<UploadDate>> {
+    let key = format!("{}{}", UPLOAD_DATE_KEY_PREFIX, hash);
+    let obj = bucket.head(key).await.map_err(|e| {
+        console_error!("failed to read upload date: {:?}", e);
+        ApiError::no_msg(500)
+    })?;
+    Ok(obj
+        .and_then(|obj| obj.custom_metadata().ok())
+        .and_then(|meta| UploadDate::parse(meta.get(UPLOAD_DATE_META_KEY)?)))
+}
+
+fn imgs_bucket(env: &Env) -> ApiResult<Bucket> {
+    env.bucket("IMGS_BUCKET").map_err(|_| {
+        console_error!("failed to get bindings to the R2 bucket");
+        ApiError::no_msg(500)
+    })
+}
+
 /// Calculate the SHA-256 hash of the given data and convert it to a hex string.
 pub fn sha256_hex(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hex::encode(hasher.finalize())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        card_scale, contact_sheet_dimensions, extract_palette, format_hex_color, overlay_watermark,
+        parse_hex_color, render_card, render_contact_sheet, ObjectKeyTemplate, UploadDate,
+    };
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    #[test]
+    fn test_overlay_watermark() {
+        const BG: Rgba<u8> = Rgba([255, 255, 255, 255]);
+        const WM: Rgba<u8> = Rgba([255, 0, 0, 255]);
+        let watermark = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 1, WM));
+
+        // 2x watermark (4 x 2) in the bottom-right corner of 10 x 10 image, 1px away from the edges
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(10, 10, BG));
+        assert!(overlay_watermark(&mut img, &watermark, 2, 1));
+        for (x, y, px) in img.pixels() {
+            let in_wm = (5..9).contains(&x) && (7..9).contains(&y);
+            assert_eq!(px, if in_wm { WM } else { BG }, "pixel at ({}, {})", x, y);
+        }
+
+        // doesn't fit with the margin: left untouched
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(5, 5, BG));
+        assert!(!overlay_watermark(&mut img, &watermark, 2, 1));
+        assert!(img.pixels().all(|(_, _, px)| px == BG));
+    }
+
+    #[test]
+    fn test_render_card() {
+        const BG: Rgba<u8> = Rgba([255, 255, 255, 255]);
+        const FG: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+        // area inside the padding is 24 x 14, so 4 x 2 image is upscaled by 6 (limited by the width),
+        // and placed at (3, 4) to be centered
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, FG));
+        assert_eq!(card_scale(&img, 30, 20, 3), 6);
+        let card = render_card(&img, 30, 20, 3, BG, None).unwrap();
+        assert_eq!(card.dimensions(), (30, 20));
+        for (x, y, px) in card.pixels() {
+            let in_art = (3..27).contains(&x) && (4..16).contains(&y);
+            assert_eq!(px, if in_art { FG } else { BG }, "pixel at ({}, {})", x, y);
+        }
+
+        // limited by the height
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 3, FG));
+        let card = render_card(&img, 30, 20, 3, BG, None).unwrap();
+        let art_px = card.pixels().filter(|&(_, _, px)| px == FG).count();
+        assert_eq!(art_px, 8 * 12);
+
+        // doesn't fit even at 1x
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 15, FG));
+        assert_eq!(card_scale(&img, 30, 20, 3), 0);
+        assert!(render_card(&img, 30, 20, 3, BG, None).is_none());
+    }
+
+    #[test]
+    fn test_render_card_watermark() {
+        const BG: Rgba<u8> = Rgba([255, 255, 255, 255]);
+        const FG: Rgba<u8> = Rgba([0, 0, 255, 255]);
+        const WM: Rgba<u8> = Rgba([255, 0, 0, 255]);
+        let watermark = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, WM));
+
+        // 3 x 3 image is upscaled by 8 and placed at (3, 3): 2x watermark in the bottom-right corner of the art, 8px away from its edges
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 3, FG));
+        let card = render_card(&img, 30, 30, 3, BG, Some(&watermark)).unwrap();
+        for (x, y, px) in card.pixels() {
+            let in_wm = (17..19).contains(&x) && (17..19).contains(&y);
+            assert_eq!(px == WM, in_wm, "pixel at ({}, {})", x, y);
+        }
+
+        // upscaled by 3 (less than WATERMARK_MIN_SCALE): not watermarked
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, FG));
+        let card = render_card(&img, 30, 30, 3, BG, Some(&watermark)).unwrap();
+        assert!(card.pixels().all(|(_, _, px)| px != WM));
+    }
+
+    #[test]
+    fn test_render_contact_sheet() {
+        const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+        const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+        const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+        let imgs = [
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, RED)),
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 1, GREEN)),
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, BLUE)),
+        ];
+
+        // 2 x 2 grid of 6 x 4 cells (the largest width and height, upscaled by 2), with 1px gaps around them
+        assert_eq!(contact_sheet_dimensions(&imgs, 2, 2, 1), (15, 11));
+        let sheet = render_contact_sheet(&imgs, 2, 2, 1, &[]);
+        assert_eq!(sheet.dimensions(), (15, 11));
+
+        // each image is centered in its cell
+        for (x, y, px) in sheet.pixels() {
+            let expected = if (2..6).contains(&x) && (1..5).contains(&y) {
+                RED
+            } else if (8..14).contains(&x) && (2..4).contains(&y) {
+                GREEN
+            } else if (3..5).contains(&x) && (7..9).contains(&y) {
+                BLUE
+            } else {
+                Rgba([0, 0, 0, 0])
+            };
+            assert_eq!(px, expected, "pixel at ({}, {})", x, y);
+        }
+
+        // columns are limited by the number of images
+        assert_eq!(contact_sheet_dimensions(&imgs[..2], 4, 1, 1), (9, 4));
+        assert_eq!(
+            render_contact_sheet(&imgs[..2], 4, 1, 1, &[]).dimensions(),
+            (9, 4)
+        );
+    }
+
+    #[test]
+    fn test_render_contact_sheet_watermark() {
+        const FG: Rgba<u8> = Rgba([0, 0, 255, 255]);
+        const WM: Rgba<u8> = Rgba([255, 0, 0, 255]);
+        let watermark = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, WM));
+        let imgs = [
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 3, FG)),
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 3, FG)),
+        ];
+
+        // only the second image is watermarked: 1x watermark in the bottom-right corner of its 12 x 12 cell, 4px away from its edges
+        let sheet = render_contact_sheet(&imgs, 2, 4, 0, &[None, Some(&watermark)]);
+        for (x, y, px) in sheet.pixels() {
+            assert_eq!(px == WM, (x, y) == (19, 7), "pixel at ({}, {})", x, y);
+        }
+
+        // not watermarked below WATERMARK_MIN_SCALE
+        let sheet = render_contact_sheet(&imgs, 2, 3, 0, &[Some(&watermark); 2]);
+        assert!(sheet.pixels().all(|(_, _, px)| px != WM));
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(parse_hex_color("#ff8000"), Some(Rgba([255, 128, 0, 255])));
+        assert_eq!(parse_hex_color("ff8000"), Some(Rgba([255, 128, 0, 255])));
+        assert_eq!(parse_hex_color("#f80"), Some(Rgba([255, 136, 0, 255])));
+        assert_eq!(parse_hex_color("#ff800080"), Some(Rgba([255, 128, 0, 128])));
+
+        assert_eq!(parse_hex_color(""), None);
+        assert_eq!(parse_hex_color("#ff80"), None);
+        assert_eq!(parse_hex_color("#gg8000"), None);
+        assert_eq!(parse_hex_color("#+f8000"), None);
+        assert_eq!(parse_hex_color("#ｆｆ0"), None);
+    }
+
+    #[test]
+    fn test_extract_palette() {
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        img.put_pixel(0, 0, Rgba([0, 0, 255, 255]));
+        img.put_pixel(1, 0, Rgba([0, 255, 0, 128]));
+        // fully transparent pixels are ignored, whatever their RGB values are
+        img.put_pixel(2, 0, Rgba([0, 0, 0, 0]));
+        img.put_pixel(3, 0, Rgba([255, 255, 255, 0]));
+        let img = DynamicImage::ImageRgba8(img);
+
+        let expected = vec![
+            Rgba([0, 0, 255, 255]),
+            Rgba([0, 255, 0, 128]),
+            Rgba([255, 0, 0, 255]),
+        ];
+        assert_eq!(extract_palette(&img, 3), Some(expected));
+        assert_eq!(extract_palette(&img, 2), None);
+
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        assert_eq!(extract_palette(&img, 1), Some(vec![]));
+    }
+
+    #[test]
+    fn test_format_hex_color() {
+        assert_eq!(format_hex_color(Rgba([255, 128, 0, 255])), "#ff8000");
+        assert_eq!(format_hex_color(Rgba([255, 128, 0, 128])), "#ff800080");
+
+        let color = Rgba([1, 2, 3, 4]);
+        assert_eq!(parse_hex_color(&format_hex_color(color)), Some(color));
+    }
+
+    #[test]
+    fn test_object_key_template() {
+        const HASH: &str = "1ea5e9febc7265432c41cf87b41f9ca1ea084bec600509add2c04048a8fec600";
+        let date = UploadDate {
+            year: 2024,
+            month: 6,
+            day: 3,
+        };
+
+        let tmpl = ObjectKeyTemplate::default();
+        assert!(!tmpl.uses_date());
+        assert_eq!(tmpl.render(HASH, 1, "png", &date), format!("{}.png", HASH));
+        assert_eq!(
+            tmpl.render(HASH, 4, "png", &date),
+            format!("{}_4x.png", HASH)
+        );
+
+        let tmpl = ObjectKeyTemplate::parse("{yyyy}/{mm}/{hash}_{scale}x.{ext}").unwrap();
+        assert!(tmpl.uses_date());
+        assert_eq!(
+            tmpl.render(HASH, 1, "png", &date),
+            format!("2024/06/{}_1x.png", HASH)
+        );
+
+        assert!(ObjectKeyTemplate::parse("{hash}.{ext}").is_err());
+        assert!(ObjectKeyTemplate::parse("{scale}.{ext}").is_err());
+        assert!(ObjectKeyTemplate::parse("{hash}{sx}.{extension}").is_err());
+        assert!(ObjectKeyTemplate::parse("{hash}{sx}.{ext").is_err());
+    }
+
+    #[test]
+    fn test_upload_date() {
+        let date = UploadDate::from_unix_secs(0);
+        assert_eq!(date.to_string(), "1970-01-01");
+
+        // 2024-02-29T12:34:56Z
+        let date = UploadDate::from_unix_secs(1709210096);
+        assert_eq!(date.to_string(), "2024-02-29");
+        assert_eq!(UploadDate::parse("2024-02-29"), Some(date));
+
+        assert_eq!(UploadDate::parse("2024-02"), None);
+        assert_eq!(UploadDate::parse("2024-02-xx"), None);
+    }
+}
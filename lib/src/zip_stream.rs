@@ -0,0 +1,129 @@
+/// Writer of ZIP archives with uncompressed (stored) entries, which produces the archive chunk by chunk.
+/// Unlike `zip::ZipWriter`, it never seeks back to fill in sizes and CRCs of entries, as it takes the whole data of each entry at once,
+/// so the archive can be streamed to the client while entries are being fetched.
+#[derive(Debug, Default)]
+pub struct ZipStream {
+    /// central directory headers of entries written so far
+    central_dir: Vec<u8>,
+    n_entries: u16,
+    /// offset of the next local header from the start of the archive
+    offset: u32,
+}
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x06054b50;
+
+/// Version 1.0, which is enough for stored entries.
+const VERSION_NEEDED: u16 = 10;
+/// Names are encoded in UTF-8.
+const FLAGS: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+/// 1980-01-01 00:00:00, the earliest date in the MS-DOS format.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+impl ZipStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the chunk of the entry: its local header followed by the data.
+    pub fn entry(&mut self, name: &str, data: &[u8]) -> Vec<u8> {
+        let crc = crc32fast::hash(data);
+        let size = data.len() as u32;
+
+        let mut chunk = Vec::with_capacity(30 + name.len() + data.len());
+        put_u32(&mut chunk, LOCAL_HEADER_SIG);
+        put_u16(&mut chunk, VERSION_NEEDED);
+        put_common_fields(&mut chunk, crc, size, name);
+        put_u16(&mut chunk, 0); // extra field length
+        chunk.extend_from_slice(name.as_bytes());
+        chunk.extend_from_slice(data);
+
+        let cd = &mut self.central_dir;
+        put_u32(cd, CENTRAL_HEADER_SIG);
+        put_u16(cd, VERSION_NEEDED); // version made by
+        put_u16(cd, VERSION_NEEDED);
+        put_common_fields(cd, crc, size, name);
+        put_u16(cd, 0); // extra field length
+        put_u16(cd, 0); // comment length
+        put_u16(cd, 0); // disk number
+        put_u16(cd, 0); // internal attributes
+        put_u32(cd, 0); // external attributes
+        put_u32(cd, self.offset);
+        cd.extend_from_slice(name.as_bytes());
+
+        self.n_entries += 1;
+        self.offset += chunk.len() as u32;
+        chunk
+    }
+
+    /// Returns the last chunk of the archive: the central directory and the end of it.
+    pub fn finish(self) -> Vec<u8> {
+        let mut chunk = self.central_dir;
+        let cd_size = chunk.len() as u32;
+        put_u32(&mut chunk, END_OF_CENTRAL_DIR_SIG);
+        put_u16(&mut chunk, 0); // disk number
+        put_u16(&mut chunk, 0); // disk where the central directory starts
+        put_u16(&mut chunk, self.n_entries); // entries on this disk
+        put_u16(&mut chunk, self.n_entries);
+        put_u32(&mut chunk, cd_size);
+        put_u32(&mut chunk, self.offset);
+        put_u16(&mut chunk, 0); // comment length
+        chunk
+    }
+}
+
+/// Puts fields shared by local and central directory headers, from flags to the length of the name.
+fn put_common_fields(buf: &mut Vec<u8>, crc: u32, size: u32, name: &str) {
+    put_u16(buf, FLAGS);
+    put_u16(buf, METHOD_STORED);
+    put_u16(buf, DOS_TIME);
+    put_u16(buf, DOS_DATE);
+    put_u32(buf, crc);
+    put_u32(buf, size); // compressed size
+    put_u32(buf, size);
+    put_u16(buf, name.len() as u16);
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::ZipStream;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_zip_stream() {
+        let entries: [(&str, &[u8]); 3] = [
+            ("a.png", b"\x89PNG dummy"),
+            ("dir/b_2x.png", &[0u8; 1000]),
+            ("metadata.json", b"{}"),
+        ];
+
+        let mut zs = ZipStream::new();
+        let mut archive = Vec::new();
+        for (name, data) in entries {
+            archive.extend(zs.entry(name, data));
+        }
+        archive.extend(zs.finish());
+
+        // readable by another implementation
+        let mut zip = ::zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), entries.len());
+        for (i, (name, data)) in entries.into_iter().enumerate() {
+            let mut file = zip.by_index(i).unwrap();
+            assert_eq!(file.name(), name);
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+}